@@ -2,7 +2,7 @@ use aws_config::BehaviorVersion;
 use aws_sdk_ssm::Client as SsmClient;
 use expo_push_notification_client::{Expo, ExpoClientOptions, ExpoPushMessage};
 use futures::future::join_all;
-use http::{header::HeaderValue, StatusCode};
+use http::StatusCode;
 use lambda_http::{Body, Error, Request, Response};
 use serde_json::{json, Value};
 use std::collections::HashMap;
@@ -10,6 +10,12 @@ use std::env;
 use supabase_rs::SupabaseClient;
 use thiserror::Error;
 
+mod auth;
+mod batch;
+mod fields;
+mod receipts;
+mod router;
+
 #[derive(Error, Debug)]
 pub enum ApiError {
     #[error("Failed to load secrets from SSM")]
@@ -22,6 +28,8 @@ pub enum ApiError {
     SupabaseInitialization,
     #[error("Failed to fetch tokens from Supabase")]
     SupabaseFetch,
+    #[error("Failed to fetch push notification receipts from Expo")]
+    ExpoReceiptFetch,
     #[error("Invalid API Key")]
     InvalidApiKey,
     #[error("Invalid request body")]
@@ -30,9 +38,81 @@ pub enum ApiError {
     BadRequest(String),
     #[error("Failed to build push message")]
     PushMessageBuild,
+    #[error("Invalid request signature: {0}")]
+    InvalidSignature(String),
+    #[error("Request timestamp outside of allowed window")]
+    ExpiredTimestamp,
+    #[error("Unknown API version: {0}")]
+    UnknownApiVersion(String),
+    #[error("Unknown endpoint: {0}")]
+    UnknownEndpoint(String),
+    #[error("API key is not permitted for endpoint: {0}")]
+    ForbiddenEndpoint(String),
+    #[error("Missing API key")]
+    MissingApiKey,
+    #[error("API key has expired")]
+    ExpiredApiKey,
+}
+
+impl ApiError {
+    /// クライアントに返すべきHTTPステータス。リクエスト内容に起因するものは4xx、
+    /// サーバー/インフラ側の問題は500として扱う。
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_)
+            | ApiError::InvalidBody
+            | ApiError::UnknownApiVersion(_)
+            | ApiError::UnknownEndpoint(_) => StatusCode::BAD_REQUEST,
+            ApiError::MissingApiKey
+            | ApiError::InvalidApiKey
+            | ApiError::ExpiredApiKey
+            | ApiError::ForbiddenEndpoint(_) => StatusCode::FORBIDDEN,
+            ApiError::InvalidSignature(_) | ApiError::ExpiredTimestamp => StatusCode::UNAUTHORIZED,
+            ApiError::SsmError
+            | ApiError::MissingSecret(_)
+            | ApiError::MissingEnvVar(_)
+            | ApiError::SupabaseInitialization
+            | ApiError::SupabaseFetch
+            | ApiError::ExpoReceiptFetch
+            | ApiError::PushMessageBuild => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// 1ページ分の取得結果。`next_token` が `Some` の間は呼び出し元がループを継続します。
+struct Page<T> {
+    items: Vec<T>,
+    next_token: Option<String>,
+}
+
+/// `next_token` を使い切るまで `fetch_page` を呼び出し、各ページの `items` を一つの `Vec` に集約します。
+///
+/// SSMの `get_parameters_by_path` のようにページ単位でレスポンスを返すAPI全般で使い回せるよう、
+/// ページ取得ロジックをクロージャとして切り出しています（Supabaseの `select` など他のページ分割API
+/// をラップする際も同じ関数を再利用できます）。
+async fn paginate<T, F, Fut>(mut fetch_page: F) -> Result<Vec<T>, ApiError>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: std::future::Future<Output = Result<Page<T>, ApiError>>,
+{
+    let mut items = Vec::new();
+    let mut next_token = None;
+
+    loop {
+        let page = fetch_page(next_token).await?;
+        items.extend(page.items);
+
+        match page.next_token {
+            Some(token) => next_token = Some(token),
+            None => break,
+        }
+    }
+
+    Ok(items)
 }
 
-/// SSM Parameter Storeから設定を一括で取得します。（ページネーションなし）
+/// SSM Parameter Storeから設定を一括で取得します。ページ単位の上限（デフォルト10件）を超えても
+/// `next_token` を辿って全件を取得します。
 pub async fn get_secrets() -> Result<HashMap<String, String>, ApiError> {
     let ssm_parameter_path = env::var("SSM_PARAMETER_PATH")
         .map_err(|_| ApiError::MissingEnvVar("SSM_PARAMETER_PATH".into()))?;
@@ -42,31 +122,46 @@ pub async fn get_secrets() -> Result<HashMap<String, String>, ApiError> {
 
     println!("Fetching parameters from SSM path: {}", ssm_parameter_path);
 
-    let mut secrets = HashMap::new();
-
-    // ページネーションを削除し、一度のリクエストで取得
-    let response = ssm_client
-        .get_parameters_by_path()
-        .path(ssm_parameter_path.clone())
-        .with_decryption(true)
-        .send()
-        .await
-        .map_err(|e| {
-            eprintln!("Failed to get parameters from SSM: {:?}", e);
-            ApiError::SsmError
-        })?;
-
-    if let Some(params) = response.parameters {
-        for param in params {
-            if let (Some(name), Some(value)) = (param.name, param.value) {
-                println!("Fetched parameter: {}, value: {}", name, value);
-                // パスからキー名のみを抽出 (e.g., /expo-push-api/supabase-key -> supabase-key)
-                if let Some(key) = name.split('/').last() {
-                    secrets.insert(key.to_string(), value);
-                }
+    let pairs = paginate(|next_token| {
+        let ssm_client = ssm_client.clone();
+        let ssm_parameter_path = ssm_parameter_path.clone();
+        async move {
+            let mut request = ssm_client
+                .get_parameters_by_path()
+                .path(ssm_parameter_path)
+                .with_decryption(true);
+            if let Some(token) = next_token {
+                request = request.next_token(token);
             }
+
+            let response = request.send().await.map_err(|e| {
+                eprintln!("Failed to get parameters from SSM: {:?}", e);
+                ApiError::SsmError
+            })?;
+
+            let items = response
+                .parameters
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|param| {
+                    let name = param.name?;
+                    let value = param.value?;
+                    println!("Fetched parameter: {}, value: {}", name, value);
+                    // パスからキー名のみを抽出 (e.g., /expo-push-api/supabase-key -> supabase-key)
+                    let key = name.split('/').last()?.to_string();
+                    Some((key, value))
+                })
+                .collect();
+
+            Ok(Page {
+                items,
+                next_token: response.next_token,
+            })
         }
-    }
+    })
+    .await?;
+
+    let secrets: HashMap<String, String> = pairs.into_iter().collect();
 
     println!("Successfully fetched secrets from SSM.");
     Ok(secrets)
@@ -92,20 +187,30 @@ pub async fn fetch_expo_push_tokens(client: &SupabaseClient) -> Result<Vec<Strin
         ApiError::SupabaseFetch
     })?;
 
+    // `expo_push_token_invalid` はレシート照会で `DeviceNotRegistered` が確定したトークンに
+    // 立てられるフラグ。死んだトークンへ送信し続けないよう除外する。
     let tokens = response
         .iter()
+        .filter(|row| row["expo_push_token_invalid"].as_bool() != Some(true))
         .filter_map(|row| row["expo_push_token"].as_str().map(|s| s.to_string()))
         .collect::<Vec<String>>();
     println!("fetched expo push tokens from supabase {:?}", tokens);
     Ok(tokens)
 }
 
+/// リクエストボディの生バイト列を返します。HMAC署名はJSONパース前のこのバイト列に対して
+/// 計算されるため、署名検証と `extract_body` の双方から参照できるよう切り出しています。
+pub fn raw_body_bytes(req: &Request) -> Result<Vec<u8>, ApiError> {
+    match req.body() {
+        Body::Text(s) => Ok(s.as_bytes().to_vec()),
+        Body::Binary(b) => Ok(b.clone()),
+        Body::Empty => Ok(Vec::new()),
+    }
+}
+
 pub async fn extract_body(req: &Request) -> Result<Value, ApiError> {
-    let body_str = match req.body() {
-        Body::Text(s) => s.to_string(),
-        Body::Binary(b) => String::from_utf8(b.to_vec()).map_err(|_| ApiError::InvalidBody)?,
-        _ => return Err(ApiError::InvalidBody),
-    };
+    let raw = raw_body_bytes(req)?;
+    let body_str = String::from_utf8(raw).map_err(|_| ApiError::InvalidBody)?;
 
     serde_json::from_str(&body_str).map_err(|_| ApiError::InvalidBody)
 }
@@ -121,17 +226,18 @@ pub fn create_error_response(
 }
 
 pub async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
-    // 1. APIキーの検証
-    let expected_key = env::var("API_KEY").expect("API_KEY not set");
-    let expected_key_value =
-        HeaderValue::from_str(&expected_key).map_err(|_| ApiError::InvalidApiKey)?;
-    if event.headers().get("x-api-key") != Some(&expected_key_value) {
-        return create_error_response(StatusCode::FORBIDDEN, "Forbidden: Invalid API Key");
-    }
+    // 1. パスを `/{version}/{endpoint}` としてルーティングする。鍵の許可エンドポイントの
+    //    照合にも使うため、認証より先に解決しておく。
+    let route = match router::parse_path(event.uri().path()) {
+        Ok(route) => route,
+        Err(err) => return create_error_response(err.status_code(), &err.to_string()),
+    };
 
     println!(
-        "Expo push notification API ver: {}",
-        env!("CARGO_PKG_VERSION")
+        "Expo push notification API ver: {}, routed as {:?}/{}",
+        env!("CARGO_PKG_VERSION"),
+        route.version,
+        route.endpoint
     );
 
     // 2. SSMから設定情報を取得
@@ -143,21 +249,124 @@ pub async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
     let expo = Expo::new(ExpoClientOptions {
         access_token: Some(expo_access_token.clone()),
     });
+    let supabase_client = initialize_supabase_client(&secrets)?;
+
+    // 3. リクエストの認証。`x-signature` / `x-timestamp` が揃っていればHMAC署名モード、
+    //    それ以外は `x-api-key` をハッシュ化して `api_keys` テーブルの有効性レコードと照合する。
+    let signature_header = event
+        .headers()
+        .get("x-signature")
+        .and_then(|v| v.to_str().ok());
+    let timestamp_header = event
+        .headers()
+        .get("x-timestamp")
+        .and_then(|v| v.to_str().ok());
+
+    if auth::has_signature_headers(signature_header, timestamp_header) {
+        // 署名モードは `api_keys` テーブルではなく、環境変数 `API_KEY` に設定された単一の
+        // 共有シークレットでHMACを検証する設計。`x-api-key` フォールバックのように
+        // クライアントごとの有効期限・許可エンドポイントを引くわけではないため、`api_keys`
+        // テーブル上でのキーの失効・ローテーションは署名モードの呼び出し元には効かない。
+        // 署名モードを無効化するには `API_KEY` 自体をローテーションする必要がある。
+        let expected_key = match env::var("API_KEY") {
+            Ok(key) => key,
+            Err(_) => {
+                let err = ApiError::MissingEnvVar("API_KEY".into());
+                return create_error_response(err.status_code(), &err.to_string());
+            }
+        };
+        let raw_body = raw_body_bytes(&event)?;
+        if let Err(err) = auth::verify_signed_request(
+            &expected_key,
+            timestamp_header.unwrap(),
+            &raw_body,
+            signature_header.unwrap(),
+            auth::timestamp_window_secs(),
+        ) {
+            return create_error_response(err.status_code(), &err.to_string());
+        }
+    } else {
+        let api_key_header = event.headers().get("x-api-key").and_then(|v| v.to_str().ok());
+        if let Err(err) = router::authorize(&supabase_client, api_key_header, &route.endpoint).await {
+            return create_error_response(err.status_code(), &err.to_string());
+        }
+    }
 
     let title;
     let body;
     let mut expo_push_tokens = vec![];
+    let mut extra_fields = fields::ExtraFields::default();
 
-    // 3. メソッドに応じて処理を分岐
-    match event.method().as_str() {
-        "GET" => {
+    // 4. 解決済みのエンドポイントに応じて処理を分岐 (HTTPメソッドではなくパスが処理内容を決める)
+    match route.endpoint.as_str() {
+        "broadcast" => {
             title = "25日だよ".to_string();
             body = "パートナーに請求しよう".to_string();
-            let supabase_client = initialize_supabase_client(&secrets)?;
             expo_push_tokens = fetch_expo_push_tokens(&supabase_client).await?;
         }
-        "POST" => {
+        "receipts" => {
+            // Expoの即時レスポンスはチケットのみを返すため、配送エラーは別途レシートAPIで照会する。
+            println!("Reconciling pending push notification receipts...");
+            let summary = receipts::reconcile_receipts(&expo, &supabase_client).await?;
+            println!(
+                "Checked {} ticket(s), deactivated {} token(s)",
+                summary.checked,
+                summary.deactivated_tokens.len()
+            );
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(
+                    json!({
+                        "checked": summary.checked,
+                        "deactivated_tokens": summary.deactivated_tokens,
+                    })
+                    .to_string()
+                    .into(),
+                )?);
+        }
+        "send" => {
             let json_body = extract_body(&event).await?;
+
+            if let Some(notifications) = json_body.get("notifications") {
+                let notifications = batch::parse_notifications(notifications)?;
+                let messages = batch::build_messages(&notifications)?;
+
+                println!(
+                    "Sending batched push notifications for {} notification(s)...",
+                    notifications.len()
+                );
+                let token_results = batch::send_in_batches(&expo, messages).await;
+
+                let new_tickets: Vec<receipts::NewTicket> = token_results
+                    .iter()
+                    .filter_map(|result| {
+                        result.ticket_id.as_ref().map(|ticket_id| receipts::NewTicket {
+                            ticket_id: ticket_id.clone(),
+                            token: result.token.clone(),
+                        })
+                    })
+                    .collect();
+                receipts::persist_tickets(&supabase_client, &new_tickets).await;
+
+                let results_json: HashMap<String, Value> = token_results
+                    .into_iter()
+                    .map(|result| {
+                        let status = if result.ok {
+                            json!({ "status": "ok" })
+                        } else {
+                            json!({ "status": "error", "error": result.error })
+                        };
+                        (result.token, status)
+                    })
+                    .collect();
+
+                return Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("Content-Type", "application/json")
+                    .body(json!({ "results": results_json }).to_string().into())?);
+            }
+
             title = json_body["title"]
                 .as_str()
                 .ok_or_else(|| ApiError::BadRequest("Title is required".into()))?
@@ -176,8 +385,10 @@ pub async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
             } else {
                 return create_error_response(StatusCode::BAD_REQUEST, "Invalid expo push token");
             }
+
+            extra_fields = fields::parse_extra_fields(&json_body)?;
         }
-        _ => return create_error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+        other => unreachable!("router::parse_path only allows known endpoints, got {}", other),
     }
 
     if expo_push_tokens.is_empty() {
@@ -192,23 +403,24 @@ pub async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
             )?);
     }
 
-    // 4. プッシュ通知メッセージの構築
+    // 5. プッシュ通知メッセージの構築
     println!(
         "Building push notification for tokens: {:?}",
         expo_push_tokens
     );
+    let tokens_for_receipts = expo_push_tokens.clone();
     let messages = expo_push_tokens
         .into_iter()
         .map(|token| {
-            ExpoPushMessage::builder(vec![token])
+            let builder = ExpoPushMessage::builder(vec![token])
                 .title(title.clone())
-                .body(body.clone())
-                .build()
-                .map_err(|_| ApiError::PushMessageBuild)
+                .body(body.clone());
+            let builder = extra_fields.apply(builder);
+            builder.build().map_err(|_| ApiError::PushMessageBuild)
         })
         .collect::<Result<Vec<_>, _>>()?;
 
-    // 5. プッシュ通知の送信
+    // 6. プッシュ通知の送信
     println!("Sending push notifications...");
     let send_futures = messages
         .into_iter()
@@ -217,6 +429,20 @@ pub async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
 
     let results = join_all(send_futures).await;
 
+    // 返ってきたチケットIDを永続化し、後続の `/v1/receipts` でのレシート照会に備える。
+    let new_tickets: Vec<receipts::NewTicket> = tokens_for_receipts
+        .into_iter()
+        .zip(results.iter())
+        .filter_map(|(token, result)| match result {
+            Ok(tickets) => tickets.first().map(|t| receipts::NewTicket {
+                ticket_id: t.id.clone(),
+                token,
+            }),
+            Err(_) => None,
+        })
+        .collect();
+    receipts::persist_tickets(&supabase_client, &new_tickets).await;
+
     let has_error = results.iter().any(|r| r.is_err());
 
     if has_error {
@@ -237,3 +463,51 @@ pub async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
             )?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn paginate_accumulates_items_across_multiple_pages() {
+        // `next_token` で3ページに分かれたレスポンスを模したモック(計5件)
+        let pages = vec![
+            Page {
+                items: vec!["a".to_string(), "b".to_string()],
+                next_token: Some("token-1".to_string()),
+            },
+            Page {
+                items: vec!["c".to_string()],
+                next_token: Some("token-2".to_string()),
+            },
+            Page {
+                items: vec!["d".to_string(), "e".to_string()],
+                next_token: None,
+            },
+        ];
+
+        let call_count = AtomicUsize::new(0);
+        let result = paginate(|next_token| {
+            let call_index = call_count.fetch_add(1, Ordering::SeqCst);
+            let expected_token = match call_index {
+                0 => None,
+                1 => Some("token-1".to_string()),
+                2 => Some("token-2".to_string()),
+                _ => panic!("unexpected extra call to fetch_page"),
+            };
+            assert_eq!(next_token, expected_token);
+
+            let page = Page {
+                items: pages[call_index].items.clone(),
+                next_token: pages[call_index].next_token.clone(),
+            };
+            async move { Ok::<_, ApiError>(page) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(result, vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(call_count.load(Ordering::SeqCst), 3);
+    }
+}
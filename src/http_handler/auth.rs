@@ -0,0 +1,60 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::ApiError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const DEFAULT_TIMESTAMP_WINDOW_SECS: i64 = 300;
+
+/// `x-signature` / `x-timestamp` の組が揃っていれば署名モード、揃っていなければ呼び出し元が
+/// `x-api-key` による認証にフォールバックします。
+pub fn has_signature_headers(signature: Option<&str>, timestamp: Option<&str>) -> bool {
+    signature.is_some() && timestamp.is_some()
+}
+
+/// `HMAC-SHA256(secret, timestamp + "." + raw_body)` を再計算し、定数時間比較で検証します。
+/// タイムスタンプがサーバー時刻から `window_secs` 秒を超えてずれている場合はリプレイとみなして拒否します。
+pub fn verify_signed_request(
+    secret: &str,
+    timestamp: &str,
+    raw_body: &[u8],
+    signature_hex: &str,
+    window_secs: i64,
+) -> Result<(), ApiError> {
+    let timestamp_secs: i64 = timestamp
+        .parse()
+        .map_err(|_| ApiError::InvalidSignature("x-timestamp is not a valid unix timestamp".into()))?;
+
+    let now_secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| ApiError::InvalidSignature("system clock error".into()))?
+        .as_secs() as i64;
+
+    if (now_secs - timestamp_secs).abs() > window_secs {
+        return Err(ApiError::ExpiredTimestamp);
+    }
+
+    let signature_bytes = hex::decode(signature_hex)
+        .map_err(|_| ApiError::InvalidSignature("x-signature is not valid hex".into()))?;
+
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .map_err(|_| ApiError::InvalidSignature("invalid HMAC key length".into()))?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(raw_body);
+
+    // `Mac::verify_slice` compares in constant time, avoiding the timing leak of `!=`.
+    mac.verify_slice(&signature_bytes)
+        .map_err(|_| ApiError::InvalidSignature("signature mismatch".into()))
+}
+
+/// 許容するタイムスタンプのずれ(秒)。`HMAC_TIMESTAMP_WINDOW_SECS` 未設定時はデフォルト300秒(5分)。
+pub fn timestamp_window_secs() -> i64 {
+    env::var("HMAC_TIMESTAMP_WINDOW_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_TIMESTAMP_WINDOW_SECS)
+}
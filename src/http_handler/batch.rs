@@ -0,0 +1,183 @@
+use expo_push_notification_client::{Expo, ExpoPushMessage};
+use futures::future::join_all;
+use serde_json::Value;
+
+use super::fields::ExtraFields;
+use super::ApiError;
+
+/// Expoの1メッセージあたりの最大受信者数。
+pub const MAX_RECIPIENTS_PER_MESSAGE: usize = 100;
+/// Expoの1 HTTPリクエストあたりの最大メッセージ数。
+pub const MAX_MESSAGES_PER_BATCH: usize = 100;
+
+/// `{ "notifications": [{ "tokens": [...], "title": ..., "body": ..., ... }, ...] }` の1要素分。
+pub struct NotificationRequest {
+    pub tokens: Vec<String>,
+    pub title: String,
+    pub body: String,
+    pub extra_fields: ExtraFields,
+}
+
+/// POSTボディの `notifications` 配列をパースし、各トークンを `is_expo_push_token` で検証します。
+pub fn parse_notifications(value: &Value) -> Result<Vec<NotificationRequest>, ApiError> {
+    let entries = value
+        .as_array()
+        .ok_or_else(|| ApiError::BadRequest("notifications must be an array".into()))?;
+
+    entries
+        .iter()
+        .map(|entry| {
+            let tokens = entry["tokens"]
+                .as_array()
+                .ok_or_else(|| ApiError::BadRequest("tokens is required".into()))?
+                .iter()
+                .map(|t| {
+                    let token = t
+                        .as_str()
+                        .ok_or_else(|| ApiError::BadRequest("tokens must be strings".into()))?;
+                    if !Expo::is_expo_push_token(token) {
+                        return Err(ApiError::BadRequest(format!(
+                            "Invalid expo push token: {}",
+                            token
+                        )));
+                    }
+                    Ok(token.to_string())
+                })
+                .collect::<Result<Vec<String>, ApiError>>()?;
+
+            let title = entry["title"]
+                .as_str()
+                .ok_or_else(|| ApiError::BadRequest("title is required".into()))?
+                .to_string();
+            let body = entry["body"]
+                .as_str()
+                .ok_or_else(|| ApiError::BadRequest("body is required".into()))?
+                .to_string();
+            let extra_fields = super::fields::parse_extra_fields(entry)?;
+
+            Ok(NotificationRequest {
+                tokens,
+                title,
+                body,
+                extra_fields,
+            })
+        })
+        .collect()
+}
+
+/// 各 `NotificationRequest` の受信者を `MAX_RECIPIENTS_PER_MESSAGE` 件ずつの `ExpoPushMessage` に
+/// 分割しつつ構築します。戻り値の `Vec<String>` は各メッセージに含まれるトークンを、メッセージと
+/// 同じ順序で保持しており、送信結果を元のトークンへマッピングするために使います。
+pub fn build_messages(
+    notifications: &[NotificationRequest],
+) -> Result<Vec<(ExpoPushMessage, Vec<String>)>, ApiError> {
+    let mut messages = Vec::new();
+
+    for notification in notifications {
+        for chunk in notification.tokens.chunks(MAX_RECIPIENTS_PER_MESSAGE) {
+            let chunk_tokens = chunk.to_vec();
+            let builder = ExpoPushMessage::builder(chunk_tokens.clone())
+                .title(notification.title.clone())
+                .body(notification.body.clone());
+            let builder = notification.extra_fields.apply(builder);
+            let message = builder.build().map_err(|_| ApiError::PushMessageBuild)?;
+            messages.push((message, chunk_tokens));
+        }
+    }
+
+    Ok(messages)
+}
+
+/// トークンごとの送信結果。`error` は送信が失敗した場合の詳細を、`ticket_id` は成功時にExpoが
+/// 発行したチケットIDを保持します（レシート照会で後から配送エラーを突き合わせるために使います）。
+pub struct TokenResult {
+    pub token: String,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub ticket_id: Option<String>,
+}
+
+/// `messages` を `MAX_MESSAGES_PER_BATCH` 件ずつのバッチに分けて送信し、各バッチ内は並行して
+/// 送信します。メッセージの送信自体が失敗した場合は全トークンへ同じエラーを反映し、成功した場合は
+/// Expoが`to`と同じ順序で返すチケットをトークンごとに対応付けます。
+pub async fn send_in_batches(
+    expo: &Expo,
+    messages: Vec<(ExpoPushMessage, Vec<String>)>,
+) -> Vec<TokenResult> {
+    let mut results = Vec::new();
+    let mut iter = messages.into_iter();
+
+    loop {
+        let batch: Vec<(ExpoPushMessage, Vec<String>)> =
+            iter.by_ref().take(MAX_MESSAGES_PER_BATCH).collect();
+        if batch.is_empty() {
+            break;
+        }
+
+        let tokens_per_message: Vec<Vec<String>> =
+            batch.iter().map(|(_, tokens)| tokens.clone()).collect();
+        let send_futures = batch
+            .into_iter()
+            .map(|(message, _)| expo.send_push_notifications(message));
+        let send_results = join_all(send_futures).await;
+
+        for (tokens, send_result) in tokens_per_message.into_iter().zip(send_results) {
+            // Expoは`to`に並べた受信者と同じ順序でチケットを1件ずつ返すため、トークンとチケットを
+            // 位置で対応付けます。送信自体が失敗した場合は全トークンへ同じエラーを反映します。
+            // HTTP呼び出しが成功しても個々のチケットの `status` が "error" のことがある
+            // (例: 無効なトークン) ため、チケット単位でも成否を確認します。
+            match send_result {
+                Ok(tickets) => {
+                    let mut tickets = tickets.into_iter();
+                    for token in tokens {
+                        match tickets.next() {
+                            Some(ticket) if ticket.status == "ok" => {
+                                results.push(TokenResult {
+                                    token,
+                                    ok: true,
+                                    error: None,
+                                    ticket_id: Some(ticket.id),
+                                });
+                            }
+                            Some(ticket) => {
+                                results.push(TokenResult {
+                                    token,
+                                    ok: false,
+                                    error: Some(
+                                        ticket
+                                            .message
+                                            .unwrap_or_else(|| "Expo rejected this recipient".into()),
+                                    ),
+                                    ticket_id: None,
+                                });
+                            }
+                            None => {
+                                results.push(TokenResult {
+                                    token,
+                                    ok: false,
+                                    error: Some(
+                                        "Expo did not return a ticket for this recipient".into(),
+                                    ),
+                                    ticket_id: None,
+                                });
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    let error = e.to_string();
+                    for token in tokens {
+                        results.push(TokenResult {
+                            token,
+                            ok: false,
+                            error: Some(error.clone()),
+                            ticket_id: None,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    results
+}
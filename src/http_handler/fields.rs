@@ -0,0 +1,122 @@
+use expo_push_notification_client::ExpoPushMessageBuilder;
+use serde_json::Value;
+
+use super::ApiError;
+
+const ALLOWED_PRIORITIES: [&str; 3] = ["default", "normal", "high"];
+
+/// `title`/`body` 以外にExpoが受け付ける任意フィールド。どれも省略可能。
+#[derive(Default)]
+pub struct ExtraFields {
+    pub data: Option<Value>,
+    pub sound: Option<String>,
+    pub badge: Option<i64>,
+    pub priority: Option<String>,
+    pub ttl: Option<i64>,
+    pub channel_id: Option<String>,
+    pub subtitle: Option<String>,
+}
+
+impl ExtraFields {
+    /// `title`/`body` を設定済みの `builder` に、値が設定されているフィールドだけを適用します。
+    /// `batch.rs` の一括送信と単発送信の両方でメッセージを組み立てる際に共通で使います。
+    pub fn apply(&self, mut builder: ExpoPushMessageBuilder) -> ExpoPushMessageBuilder {
+        if let Some(data) = &self.data {
+            builder = builder.data(data.clone());
+        }
+        if let Some(sound) = &self.sound {
+            builder = builder.sound(sound.clone());
+        }
+        if let Some(badge) = self.badge {
+            builder = builder.badge(badge);
+        }
+        if let Some(priority) = &self.priority {
+            builder = builder.priority(priority.clone());
+        }
+        if let Some(ttl) = self.ttl {
+            builder = builder.ttl(ttl);
+        }
+        if let Some(channel_id) = &self.channel_id {
+            builder = builder.channel_id(channel_id.clone());
+        }
+        if let Some(subtitle) = &self.subtitle {
+            builder = builder.subtitle(subtitle.clone());
+        }
+        builder
+    }
+}
+
+/// リクエストボディから任意フィールドを取り出し、値を検証します。不正な値があれば、
+/// どのフィールドが原因かを `ApiError::BadRequest` で返します。
+pub fn parse_extra_fields(value: &Value) -> Result<ExtraFields, ApiError> {
+    let data = value.get("data").cloned();
+
+    let sound = match value.get("sound") {
+        None | Some(Value::Null) => None,
+        Some(v) => Some(
+            v.as_str()
+                .filter(|s| !s.is_empty())
+                .ok_or_else(|| ApiError::BadRequest("sound".into()))?
+                .to_string(),
+        ),
+    };
+
+    let badge = match value.get("badge") {
+        None | Some(Value::Null) => None,
+        Some(v) => Some(
+            v.as_i64()
+                .filter(|n| *n >= 0)
+                .ok_or_else(|| ApiError::BadRequest("badge".into()))?,
+        ),
+    };
+
+    let priority = match value.get("priority") {
+        None | Some(Value::Null) => None,
+        Some(v) => {
+            let priority = v.as_str().ok_or_else(|| ApiError::BadRequest("priority".into()))?;
+            if !ALLOWED_PRIORITIES.contains(&priority) {
+                return Err(ApiError::BadRequest("priority".into()));
+            }
+            Some(priority.to_string())
+        }
+    };
+
+    let ttl = match value.get("ttl") {
+        None | Some(Value::Null) => None,
+        Some(v) => Some(
+            v.as_i64()
+                .filter(|n| *n >= 0)
+                .ok_or_else(|| ApiError::BadRequest("ttl".into()))?,
+        ),
+    };
+
+    let channel_id = match value.get("channel_id") {
+        None | Some(Value::Null) => None,
+        Some(v) => Some(
+            v.as_str()
+                .filter(|s| !s.is_empty())
+                .ok_or_else(|| ApiError::BadRequest("channel_id".into()))?
+                .to_string(),
+        ),
+    };
+
+    let subtitle = match value.get("subtitle") {
+        None | Some(Value::Null) => None,
+        Some(v) => Some(
+            v.as_str()
+                .filter(|s| !s.is_empty())
+                .ok_or_else(|| ApiError::BadRequest("subtitle".into()))?
+                .to_string(),
+        ),
+    };
+
+    Ok(ExtraFields {
+        data,
+        sound,
+        badge,
+        priority,
+        ttl,
+        channel_id,
+        subtitle,
+    })
+}
@@ -0,0 +1,173 @@
+use expo_push_notification_client::Expo;
+use serde_json::json;
+use supabase_rs::SupabaseClient;
+
+use super::ApiError;
+
+const PUSH_TICKETS_TABLE: &str = "push_tickets";
+const USERS_TABLE: &str = "users";
+
+/// 送信直後にExpoから返る `id` と、そのチケットが対象にしていたトークンの組。
+pub struct NewTicket {
+    pub ticket_id: String,
+    pub token: String,
+}
+
+struct PendingTicket {
+    row_id: String,
+    ticket_id: String,
+    token: String,
+}
+
+/// Expoの即時レスポンスは *チケット* のみを返し、`DeviceNotRegistered` のような配送エラーは
+/// 後からレシートAPIで判明するため、`push_tickets` テーブルに未処理として保存しておきます。
+/// 通知自体はすでにExpoへ送信済みのため、保存に失敗してもリクエスト全体は失敗させず、
+/// 該当チケットのレシート照会を諦めるだけに留めます(best-effort)。
+pub async fn persist_tickets(client: &SupabaseClient, tickets: &[NewTicket]) {
+    for ticket in tickets {
+        if let Err(e) = client
+            .insert(
+                PUSH_TICKETS_TABLE,
+                json!({
+                    "ticket_id": ticket.ticket_id,
+                    "token": ticket.token,
+                    "processed": false,
+                }),
+            )
+            .await
+        {
+            eprintln!("Failed to persist ticket {}: {:?}", ticket.ticket_id, e);
+        }
+    }
+}
+
+async fn fetch_pending_tickets(client: &SupabaseClient) -> Result<Vec<PendingTicket>, ApiError> {
+    let response = client
+        .select(PUSH_TICKETS_TABLE)
+        .execute()
+        .await
+        .map_err(|e| {
+            eprintln!("Error fetching pending tickets: {:?}", e);
+            ApiError::SupabaseFetch
+        })?;
+
+    let tickets = response
+        .iter()
+        .filter(|row| row["processed"].as_bool() == Some(false))
+        .filter_map(|row| {
+            let row_id = row["id"].as_str()?.to_string();
+            let ticket_id = row["ticket_id"].as_str()?.to_string();
+            let token = row["token"].as_str()?.to_string();
+            Some(PendingTicket {
+                row_id,
+                ticket_id,
+                token,
+            })
+        })
+        .collect();
+    Ok(tickets)
+}
+
+async fn mark_ticket_processed(client: &SupabaseClient, row_id: &str) -> Result<(), ApiError> {
+    client
+        .update(PUSH_TICKETS_TABLE, row_id, json!({ "processed": true }))
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to mark ticket {} as processed: {:?}", row_id, e);
+            ApiError::SupabaseFetch
+        })?;
+    Ok(())
+}
+
+async fn find_user_row_id_by_token(
+    client: &SupabaseClient,
+    token: &str,
+) -> Result<Option<String>, ApiError> {
+    let response = client.select(USERS_TABLE).execute().await.map_err(|e| {
+        eprintln!("Error looking up user by token: {:?}", e);
+        ApiError::SupabaseFetch
+    })?;
+
+    Ok(response
+        .iter()
+        .find(|row| row["expo_push_token"].as_str() == Some(token))
+        .and_then(|row| row["id"].as_str().map(|s| s.to_string())))
+}
+
+/// `DeviceNotRegistered` が確定したトークンを `users` テーブル上で無効フラグ付けします。行ごと
+/// 削除すると監査時に復元できなくなるため、フラグを立てて以降の送信対象から除外する方を選びます。
+async fn flag_invalid_token(client: &SupabaseClient, token: &str) -> Result<(), ApiError> {
+    if let Some(row_id) = find_user_row_id_by_token(client, token).await? {
+        client
+            .update(
+                USERS_TABLE,
+                &row_id,
+                json!({ "expo_push_token_invalid": true }),
+            )
+            .await
+            .map_err(|e| {
+                eprintln!("Failed to flag invalid token for user {}: {:?}", row_id, e);
+                ApiError::SupabaseFetch
+            })?;
+    }
+    Ok(())
+}
+
+/// 未処理チケットの件数と、今回 `DeviceNotRegistered` を理由に無効化したトークンの一覧。
+pub struct ReconciliationSummary {
+    pub checked: usize,
+    pub deactivated_tokens: Vec<String>,
+}
+
+/// `push_tickets` の未処理チケットをExpoのレシートAPIで照会し、`DeviceNotRegistered` が
+/// 返ってきたトークンを `users` 側で無効化します。これにより死んだトークンへの再送が止まります。
+pub async fn reconcile_receipts(
+    expo: &Expo,
+    supabase: &SupabaseClient,
+) -> Result<ReconciliationSummary, ApiError> {
+    let pending = fetch_pending_tickets(supabase).await?;
+    if pending.is_empty() {
+        return Ok(ReconciliationSummary {
+            checked: 0,
+            deactivated_tokens: vec![],
+        });
+    }
+
+    let ticket_ids: Vec<String> = pending.iter().map(|t| t.ticket_id.clone()).collect();
+    let receipts = expo
+        .get_push_notification_receipts(ticket_ids)
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to fetch push notification receipts: {:?}", e);
+            ApiError::ExpoReceiptFetch
+        })?;
+
+    let mut deactivated_tokens = Vec::new();
+
+    for ticket in &pending {
+        // Expoはレシートの生成が完了したチケットしか返さないため、まだ存在しないチケットは
+        // 未処理のまま残し、次回の照会で改めて問い合わせる。ここで processed にしてしまうと
+        // 後から判明する `DeviceNotRegistered` を二度と検出できなくなる。
+        let Some(receipt) = receipts.get(&ticket.ticket_id) else {
+            continue;
+        };
+
+        let is_unregistered = receipt.status == "error"
+            && receipt
+                .details
+                .as_ref()
+                .and_then(|details| details.error.as_deref())
+                == Some("DeviceNotRegistered");
+
+        if is_unregistered {
+            flag_invalid_token(supabase, &ticket.token).await?;
+            deactivated_tokens.push(ticket.token.clone());
+        }
+        mark_ticket_processed(supabase, &ticket.row_id).await?;
+    }
+
+    Ok(ReconciliationSummary {
+        checked: pending.len(),
+        deactivated_tokens,
+    })
+}
@@ -0,0 +1,118 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use blake3::hash as blake3_hash;
+use supabase_rs::SupabaseClient;
+
+use super::ApiError;
+
+const API_KEYS_TABLE: &str = "api_keys";
+
+/// サポートしているAPIバージョン。新しいバージョンを追加する際はここに増やす。
+#[derive(Debug, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+}
+
+/// `/v1/send` のようなパスを分解した結果。
+pub struct Route {
+    pub version: ApiVersion,
+    pub endpoint: String,
+}
+
+/// `path` を `/{version}/{endpoint}` としてパースします。未知のバージョン・エンドポイントは
+/// それぞれ別のエラーとして返し、クライアントが原因を特定しやすいようにします。
+pub fn parse_path(path: &str) -> Result<Route, ApiError> {
+    let mut segments = path.trim_start_matches('/').splitn(2, '/');
+    let version = segments
+        .next()
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| ApiError::UnknownApiVersion("".into()))?;
+    let endpoint = segments
+        .next()
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| ApiError::UnknownEndpoint("".into()))?;
+
+    let version = match version {
+        "v1" => ApiVersion::V1,
+        other => return Err(ApiError::UnknownApiVersion(other.to_string())),
+    };
+
+    match endpoint {
+        "send" | "broadcast" | "receipts" => Ok(Route {
+            version,
+            endpoint: endpoint.to_string(),
+        }),
+        other => Err(ApiError::UnknownEndpoint(other.to_string())),
+    }
+}
+
+/// 鍵1件分の有効性レコード。`key_hash` は生の鍵文字列をBlake3でハッシュしたもの。
+struct KeyRecord {
+    key_hash: String,
+    expires_at: Option<i64>,
+    allowed_endpoints: Vec<String>,
+}
+
+fn hash_key(raw_key: &str) -> String {
+    blake3_hash(raw_key.as_bytes()).to_hex().to_string()
+}
+
+async fn fetch_key_records(client: &SupabaseClient) -> Result<Vec<KeyRecord>, ApiError> {
+    let response = client.select(API_KEYS_TABLE).execute().await.map_err(|e| {
+        eprintln!("Error fetching api key records: {:?}", e);
+        ApiError::SupabaseFetch
+    })?;
+
+    let records = response
+        .iter()
+        .filter_map(|row| {
+            let key_hash = row["key_hash"].as_str()?.to_string();
+            let expires_at = row["expires_at"].as_i64();
+            let allowed_endpoints = row["allowed_endpoints"]
+                .as_array()?
+                .iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect();
+            Some(KeyRecord {
+                key_hash,
+                expires_at,
+                allowed_endpoints,
+            })
+        })
+        .collect();
+
+    Ok(records)
+}
+
+/// `raw_key` をハッシュ化して `api_keys` テーブルから照合し、有効期限切れでなく、かつ
+/// `endpoint` へのアクセスが許可されているかを確認します。
+pub async fn authorize(
+    client: &SupabaseClient,
+    raw_key: Option<&str>,
+    endpoint: &str,
+) -> Result<(), ApiError> {
+    let raw_key = raw_key.ok_or(ApiError::MissingApiKey)?;
+    let key_hash = hash_key(raw_key);
+
+    let records = fetch_key_records(client).await?;
+    let record = records
+        .into_iter()
+        .find(|record| record.key_hash == key_hash)
+        .ok_or(ApiError::InvalidApiKey)?;
+
+    if let Some(expires_at) = record.expires_at {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| ApiError::ExpiredApiKey)?
+            .as_secs() as i64;
+        if now > expires_at {
+            return Err(ApiError::ExpiredApiKey);
+        }
+    }
+
+    if !record.allowed_endpoints.iter().any(|allowed| allowed == endpoint) {
+        return Err(ApiError::ForbiddenEndpoint(endpoint.to_string()));
+    }
+
+    Ok(())
+}